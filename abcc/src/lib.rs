@@ -22,10 +22,34 @@
 //! possible to pause & resume a computation, or to partially evaluate
 //! a function with only some of its arguments.
 
+use std::cell::Cell;
 use std::rc::Rc;
 
-/// An error that may occur during a computation.
-pub enum Error {
+/// A byte range into a piece of source text.
+///
+/// Sequence and quote nodes derive their span as the hull of their
+/// children's spans; combinators and variables get the exact token
+/// range they were read from.
+#[derive(Clone)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+  pub source: Rc<str>,
+}
+
+impl Span {
+  /// The smallest span that encloses both `self` and `other`.
+  pub fn hull(&self, other: &Span) -> Span {
+    return Span {
+      start: self.start.min(other.start),
+      end: self.end.max(other.end),
+      source: self.source.clone(),
+    };
+  }
+}
+
+/// The kind of an error that may occur during a computation.
+pub enum ErrorKind {
   Space,
   Time,
   Type,
@@ -33,6 +57,38 @@ pub enum Error {
   Syntax,
   Stub,
   Bug,
+  /// A handler does not recognize the effect it was given, and
+  /// expects it to be handled elsewhere, e.g. by `compose_handlers`'
+  /// outer handler. Distinct from `Stub`, which signals a genuinely
+  /// unimplemented code path rather than ordinary handler fallthrough.
+  Decline,
+}
+
+/// An error that may occur during a computation, optionally located
+/// at a span in the object's originating source.
+pub struct Error {
+  pub kind: ErrorKind,
+  pub span: Option<Span>,
+  pub message: Option<Rc<str>>,
+}
+
+impl Error {
+  /// Construct an error with no span or message attached.
+  pub fn new(kind: ErrorKind) -> Self {
+    return Error { kind, span: None, message: None };
+  }
+
+  /// Attach a span to this error.
+  pub fn with_span(mut self, span: Span) -> Self {
+    self.span = Some(span);
+    return self;
+  }
+
+  /// Attach a message to this error.
+  pub fn with_message(mut self, message: Rc<str>) -> Self {
+    self.message = Some(message);
+    return self;
+  }
 }
 
 /// The result of a computation.
@@ -51,6 +107,25 @@ pub enum Constant {
   Bang,
 }
 
+/// An interned variable name. Two symbols are equal if and only if
+/// they were interned from the same name, so comparing symbols is
+/// O(1) instead of comparing the underlying strings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(pub u32);
+
+/// An index identifying one node in a `Container::save` snapshot's
+/// node table. Two objects that share a `NodeId` (see
+/// `Container::node_id`) are the same subterm, so `save` writes that
+/// subterm once and every other occurrence is just a reference to
+/// its id, letting a DAG round-trip without exponential blowup.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub u64);
+
+/// What `Container::load` recovers from a snapshot: the root objects,
+/// in the order given to `save`, and the redex `save` was given, if
+/// any.
+pub type Snapshot<O> = (Vec<O>, Option<O>);
+
 /// A variable that may be replaced with a value.
 pub struct Variable(pub Rc<str>);
 
@@ -71,22 +146,71 @@ pub trait Container {
   /// Serialize an object to a string.
   fn show(&self, obj: Self::Object) -> Result<String>;
 
-  /// Get the object associated with a variable.
-  fn get(&self, key: Variable) -> Result<Option<Self::Object>>;
-  /// Associate a value with a variable.
-  fn put(&mut self, key: Variable, value: Self::Object) -> Result<Self::Object>;
-  /// Remove the value associated with a variable.
-  fn delete(&mut self, key: Variable) -> Result<Self::Object>;
+  /// Write a compact binary encoding of the objects reachable from
+  /// `roots`, together with the current variable bindings, to `out`.
+  /// Every object is looked up by `node_id` before it is written, so
+  /// subterms that share an id are written once, and every later
+  /// occurrence is only a reference to that id — a DAG round-trips
+  /// without exponential blowup. If `redex` names the subterm a
+  /// `normalize` call was in the middle of rewriting, its position is
+  /// recorded too, so the computation can be resumed by `load`
+  /// rather than only restarted from `roots`. Unlike `show`, this is
+  /// lossless over sharing and suitable for large states.
+  fn save(
+    &self,
+    roots: Vec<Self::Object>,
+    redex: Option<Self::Object>,
+    out: &mut dyn std::io::Write) -> Result<()>;
+  /// Read a snapshot written by `save`, restoring the variable
+  /// bindings and node table it captured. Returns the root objects,
+  /// freshly allocated in this container, in the order given to
+  /// `save`, together with the redex `save` was given, if any.
+  fn load(&mut self, src: &mut dyn std::io::Read) -> Result<Snapshot<Self::Object>>;
+  /// Get the `NodeId` that identifies this object's position in the
+  /// shared node table that `save` writes. Two objects with the same
+  /// id are the same subterm; this is how `save` detects sharing
+  /// without requiring `Self::Object` to support equality.
+  fn node_id(&self, obj: Self::Object) -> Result<NodeId>;
+
+  /// Get the object associated with an interned variable.
+  fn get(&self, key: Symbol) -> Result<Option<Self::Object>>;
+  /// Associate a value with an interned variable.
+  fn put(&mut self, key: Symbol, value: Self::Object) -> Result<Self::Object>;
+  /// Remove the value associated with an interned variable.
+  fn delete(&mut self, key: Symbol) -> Result<Self::Object>;
 
   /// Rewrite an object until it reaches normal form, or until an
   /// effort quota is exhausted.
   fn normalize(&mut self, obj: Self::Object) -> Result<Self::Object>;
 
-  /// Like `normalize`, but executes bangs using a handler.
-  fn execute(&mut self, obj: Self::Object, ctx: dyn Handler<Self>) -> Result<Self::Object>;
+  /// Like `normalize`, but executes bangs using a handler. The
+  /// handler is installed for the dynamic extent of this call, so it
+  /// composes naturally with `scoped` and the `Shift`/`Reset`
+  /// delimited-continuation combinators.
+  fn execute(&mut self, obj: Self::Object, ctx: &mut dyn Handler<Self>) -> Result<Self::Object>;
 
   /// Extend an object using an analysis of the current environment.
+  /// Given an open object (one where `has_variable` is true, i.e. it
+  /// references names not yet bound), scans the variables currently
+  /// stored via `put` for bindings whose normal form would close the
+  /// object, and returns it extended with `new_sequence`/
+  /// `new_variable` references to those bindings. Equivalent to
+  /// taking the first candidate from `complete_candidates`.
   fn complete(&mut self, obj: Self::Object) -> Result<Self::Object>;
+  /// Like `complete`, but returns up to `limit` ranked candidates
+  /// instead of committing to the first one, so an editor front-end
+  /// can present suggestions. Each candidate pairs the symbol that
+  /// would be auto-imported (keyed the same way as `put`) with the
+  /// object extended to reference it; use `resolve` to recover its
+  /// name for display.
+  fn complete_candidates(
+    &mut self,
+    obj: Self::Object,
+    limit: usize) -> Result<Vec<(Symbol, Self::Object)>>;
+  /// Given a partial sequence ending in a quotation, propose the
+  /// combinators (`Apply`, `Copy`, `Drop`, ...) that would typecheck
+  /// as the next token, in the style of postfix completion.
+  fn complete_postfix(&mut self, obj: Self::Object) -> Result<Vec<Constant>>;
 
   /// Create an identity program.
   fn new_identity(&self) -> Result<Self::Object>;
@@ -143,8 +267,9 @@ pub trait Container {
 
   /// Get the name associated with a constant.
   fn get_constant_name(&self, obj: Self::Object) -> Result<Constant>;
-  /// Get the name associated with a variable.
-  fn get_variable_name(&self, obj: Self::Object) -> Result<Variable>;
+  /// Get the interned name associated with a variable. Use `resolve`
+  /// to recover the underlying string.
+  fn get_variable_name(&self, obj: Self::Object) -> Result<Symbol>;
   /// Get the body of a comment.
   fn get_comment_body(&self, obj: Self::Object) -> Result<Rc<str>>;
   /// Get the body of a quotation.
@@ -154,9 +279,66 @@ pub trait Container {
   /// Get the second element of a sequence.
   fn get_sequence_snd(&self, obj: Self::Object) -> Result<Self::Object>;
 
-  /// Collect garbage, protecting the given objects and their
-  /// children.
-  fn collect(&mut self, xs: Vec<Self::Object>) -> Result<()>;
+  /// Reclaim up to `limit` nodes retired at least two epochs ago,
+  /// protecting the given objects and their children. Unlike a
+  /// stop-the-world collection, this interleaves with rewriting:
+  /// call it periodically instead of blocking on it. Returns the
+  /// number of nodes actually freed. Symbols interned by `intern`
+  /// that are no longer reachable from any live object are recycled
+  /// along with the nodes that referenced them.
+  fn collect(&mut self, xs: Vec<Self::Object>, limit: usize) -> Result<usize>;
+
+  /// Pin the container at the current epoch. While the returned
+  /// guard is live, no object retired in this epoch (or later) may
+  /// be freed; `normalize` and `execute` hold a guard for the
+  /// duration of their traversal.
+  fn pin(&self) -> Guard;
+
+  /// Get the span into the originating source that this object was
+  /// read from, if it has one. Spans are propagated from a redex to
+  /// its reduct, so this remains stable across rewriting.
+  fn get_span(&self, obj: Self::Object) -> Result<Option<Span>>;
+
+  /// Intern a variable name, so that future occurrences of the same
+  /// name compare in O(1) instead of allocating and comparing a
+  /// string. `get`, `put`, and `delete` key on the returned symbol.
+  fn intern(&mut self, name: Rc<str>) -> Symbol;
+  /// Recover the name a symbol was interned from.
+  fn resolve(&self, sym: Symbol) -> Rc<str>;
+}
+
+/// A handle returned by `Container::pin`. Dropping it decrements the
+/// live-guard count for the epoch it pinned, which is how the
+/// container learns the traversal is done; the epoch can only
+/// advance once every guard live at that epoch has been dropped. No
+/// object retired in epoch `e` is freed until every guard that was
+/// live at epoch `e` has been dropped. `Guard` cannot be constructed
+/// except through `new`, so a container's live-guard count cannot be
+/// tampered with independently of `pin`.
+pub struct Guard {
+  epoch: u64,
+  live: Rc<Cell<u64>>,
+}
+
+impl Guard {
+  /// Pin `epoch`, incrementing `live`'s count. `Container::pin`
+  /// implementations call this with the epoch counter and live-guard
+  /// count they maintain internally.
+  pub fn new(epoch: u64, live: Rc<Cell<u64>>) -> Self {
+    live.set(live.get() + 1);
+    return Guard { epoch, live };
+  }
+
+  /// The epoch this guard pins.
+  pub fn epoch(&self) -> u64 {
+    return self.epoch;
+  }
+}
+
+impl Drop for Guard {
+  fn drop(&mut self) {
+    self.live.set(self.live.get() - 1);
+  }
 }
 
 /// A delegate to provide an effectful interpretation of bangs, on
@@ -164,3 +346,267 @@ pub trait Container {
 pub trait Handler<C: Container> {
   fn execute(&mut self, args: Vec<C::Object>, ctx: &mut C) -> Result<Vec<C::Object>>;
 }
+
+/// A handler that gives `inner` the first chance to perform an
+/// effect, and forwards anything `inner` declines (an `Err` with
+/// `ErrorKind::Decline`) to `outer`. Nesting `compose_handlers` builds
+/// a stack of handlers out of smaller, reusable ones instead of one
+/// monolithic handler per program.
+pub struct ComposeHandler<A, B> {
+  outer: A,
+  inner: B,
+}
+
+/// Stack `inner` in front of `outer`: `inner` is tried first, and
+/// effects it does not handle are forwarded to `outer`.
+pub fn compose_handlers<A, B>(outer: A, inner: B) -> ComposeHandler<A, B> {
+  return ComposeHandler { outer, inner };
+}
+
+impl<C: Container, A: Handler<C>, B: Handler<C>> Handler<C> for ComposeHandler<A, B>
+where C::Object: Clone {
+  fn execute(&mut self, args: Vec<C::Object>, ctx: &mut C) -> Result<Vec<C::Object>> {
+    match self.inner.execute(args.clone(), ctx) {
+      Err(Error { kind: ErrorKind::Decline, .. }) => self.outer.execute(args, ctx),
+      result => result,
+    }
+  }
+}
+
+/// Run `obj` through `ctx.execute`, installing `handler` for the
+/// dynamic extent of that one call. A thin wrapper that names the
+/// pattern of scoping a handler to a single `execute`, pairing
+/// naturally with `Shift`/`Reset`.
+pub fn scoped<C: Container>(
+  ctx: &mut C,
+  obj: C::Object,
+  handler: &mut dyn Handler<C>) -> Result<C::Object> {
+  return ctx.execute(obj, handler);
+}
+
+/// A handler that performs no effects itself; it only records the
+/// arguments it was asked to handle, in order. Useful for testing
+/// effectful programs without a real handler.
+pub struct LogHandler<C: Container> {
+  pub log: Vec<Vec<C::Object>>,
+}
+
+impl<C: Container> LogHandler<C> {
+  /// Create a handler with an empty log.
+  pub fn new() -> Self {
+    return LogHandler { log: Vec::new() };
+  }
+}
+
+impl<C: Container> Default for LogHandler<C> {
+  fn default() -> Self {
+    return LogHandler::new();
+  }
+}
+
+impl<C: Container> Handler<C> for LogHandler<C>
+where C::Object: Clone {
+  fn execute(&mut self, args: Vec<C::Object>, _ctx: &mut C) -> Result<Vec<C::Object>> {
+    self.log.push(args.clone());
+    return Ok(args);
+  }
+}
+
+/// A predicate over a bang's arguments, used to decide whether a
+/// `FilterHandler` rule applies.
+pub type Predicate<C> = Box<dyn Fn(&C, &[<C as Container>::Object]) -> bool>;
+
+/// A function that replaces a bang's arguments with new ones, used by
+/// `FilterAction::Rewrite`.
+pub type RewriteFn<C> =
+  Box<dyn Fn(&mut C, Vec<<C as Container>::Object>) -> Result<Vec<<C as Container>::Object>>>;
+
+/// What a `FilterHandler` rule does with an effect whose arguments
+/// matched its predicate.
+pub enum FilterAction<C: Container> {
+  /// Hand the effect to the wrapped handler.
+  Perform,
+  /// Discard the effect, returning its arguments unchanged.
+  Drop,
+  /// Replace the effect with the result of the given function.
+  Rewrite(RewriteFn<C>),
+}
+
+/// A handler that pattern-matches the arguments of a bang against an
+/// ordered list of predicates, and performs, drops, or rewrites the
+/// effect depending on the action of the first rule that matches.
+/// Effects matched by no rule are forwarded to the wrapped handler.
+pub struct FilterHandler<C: Container> {
+  rules: Vec<(Predicate<C>, FilterAction<C>)>,
+  inner: Box<dyn Handler<C>>,
+}
+
+impl<C: Container> FilterHandler<C> {
+  /// Create a filter with no rules, forwarding every effect to
+  /// `inner`.
+  pub fn new(inner: Box<dyn Handler<C>>) -> Self {
+    return FilterHandler { rules: Vec::new(), inner };
+  }
+
+  /// Add a rule: when `matches` holds of a bang's arguments, apply
+  /// `action` instead of consulting later rules. The wrapped handler
+  /// is then reached only if `action` is `FilterAction::Perform`.
+  pub fn rule(mut self, matches: Predicate<C>, action: FilterAction<C>) -> Self {
+    self.rules.push((matches, action));
+    return self;
+  }
+}
+
+impl<C: Container> Handler<C> for FilterHandler<C> {
+  fn execute(&mut self, args: Vec<C::Object>, ctx: &mut C) -> Result<Vec<C::Object>> {
+    for (matches, action) in self.rules.iter() {
+      if !matches(ctx, &args) {
+        continue;
+      }
+      return match action {
+        FilterAction::Perform => self.inner.execute(args, ctx),
+        FilterAction::Drop => Ok(args),
+        FilterAction::Rewrite(rewrite) => rewrite(ctx, args),
+      };
+    }
+    return self.inner.execute(args, ctx);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A `Container` whose methods are never called by the tests
+  /// below; it only exists so `Handler<MockContainer>` has a
+  /// concrete type to be generic over.
+  struct MockContainer;
+
+  impl Container for MockContainer {
+    type Object = i32;
+
+    fn read(&mut self, _src: &str) -> Result<i32> { unimplemented!() }
+    fn show(&self, _obj: i32) -> Result<String> { unimplemented!() }
+    fn save(&self, _roots: Vec<i32>, _redex: Option<i32>, _out: &mut dyn std::io::Write) -> Result<()> { unimplemented!() }
+    fn load(&mut self, _src: &mut dyn std::io::Read) -> Result<Snapshot<i32>> { unimplemented!() }
+    fn node_id(&self, _obj: i32) -> Result<NodeId> { unimplemented!() }
+    fn get(&self, _key: Symbol) -> Result<Option<i32>> { unimplemented!() }
+    fn put(&mut self, _key: Symbol, _value: i32) -> Result<i32> { unimplemented!() }
+    fn delete(&mut self, _key: Symbol) -> Result<i32> { unimplemented!() }
+    fn normalize(&mut self, _obj: i32) -> Result<i32> { unimplemented!() }
+    fn execute(&mut self, _obj: i32, _ctx: &mut dyn Handler<Self>) -> Result<i32> { unimplemented!() }
+    fn complete(&mut self, _obj: i32) -> Result<i32> { unimplemented!() }
+    fn complete_candidates(&mut self, _obj: i32, _limit: usize) -> Result<Vec<(Symbol, i32)>> { unimplemented!() }
+    fn complete_postfix(&mut self, _obj: i32) -> Result<Vec<Constant>> { unimplemented!() }
+    fn new_identity(&self) -> Result<i32> { unimplemented!() }
+    fn new_constant(&mut self, _name: Constant) -> Result<i32> { unimplemented!() }
+    fn new_variable(&mut self, _name: Variable) -> Result<i32> { unimplemented!() }
+    fn new_comment(&mut self, _body: Rc<str>) -> Result<i32> { unimplemented!() }
+    fn new_quote(&mut self, _body: i32) -> Result<i32> { unimplemented!() }
+    fn new_sequence(&mut self, _fst: i32, _snd: i32) -> Result<i32> { unimplemented!() }
+    fn is_identity(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_constant(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_variable(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_comment(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_quote(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_sequence(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_prompt(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn is_bang(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_constant(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_variable(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_comment(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_quote(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_prompt(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn has_bang(&self, _obj: i32) -> Result<bool> { unimplemented!() }
+    fn get_constant_name(&self, _obj: i32) -> Result<Constant> { unimplemented!() }
+    fn get_variable_name(&self, _obj: i32) -> Result<Symbol> { unimplemented!() }
+    fn get_comment_body(&self, _obj: i32) -> Result<Rc<str>> { unimplemented!() }
+    fn get_quote_body(&self, _obj: i32) -> Result<i32> { unimplemented!() }
+    fn get_sequence_fst(&self, _obj: i32) -> Result<i32> { unimplemented!() }
+    fn get_sequence_snd(&self, _obj: i32) -> Result<i32> { unimplemented!() }
+    fn collect(&mut self, _xs: Vec<i32>, _limit: usize) -> Result<usize> { unimplemented!() }
+    fn pin(&self) -> Guard { unimplemented!() }
+    fn get_span(&self, _obj: i32) -> Result<Option<Span>> { unimplemented!() }
+    fn intern(&mut self, _name: Rc<str>) -> Symbol { unimplemented!() }
+    fn resolve(&self, _sym: Symbol) -> Rc<str> { unimplemented!() }
+  }
+
+  /// Returns its arguments unchanged.
+  struct Echo;
+
+  impl Handler<MockContainer> for Echo {
+    fn execute(&mut self, args: Vec<i32>, _ctx: &mut MockContainer) -> Result<Vec<i32>> {
+      return Ok(args);
+    }
+  }
+
+  /// Always declines with `ErrorKind::Decline`, as a handler does
+  /// when it does not recognize an effect.
+  struct Decline;
+
+  impl Handler<MockContainer> for Decline {
+    fn execute(&mut self, _args: Vec<i32>, _ctx: &mut MockContainer) -> Result<Vec<i32>> {
+      return Err(Error::new(ErrorKind::Decline));
+    }
+  }
+
+  /// Always fails with `ErrorKind::Type`, i.e. a real error that is
+  /// not a "this handler doesn't apply" signal.
+  struct Fail;
+
+  impl Handler<MockContainer> for Fail {
+    fn execute(&mut self, _args: Vec<i32>, _ctx: &mut MockContainer) -> Result<Vec<i32>> {
+      return Err(Error::new(ErrorKind::Type));
+    }
+  }
+
+  /// Panics if invoked; used to prove a handler was never consulted.
+  struct Unreachable;
+
+  impl Handler<MockContainer> for Unreachable {
+    fn execute(&mut self, _args: Vec<i32>, _ctx: &mut MockContainer) -> Result<Vec<i32>> {
+      panic!("handler should not have been reached");
+    }
+  }
+
+  #[test]
+  fn compose_handlers_forwards_to_outer_on_decline() {
+    let mut composed = compose_handlers(Echo, Decline);
+    let mut ctx = MockContainer;
+    match composed.execute(vec![1, 2, 3], &mut ctx) {
+      Ok(result) => assert_eq!(result, vec![1, 2, 3]),
+      Err(_) => panic!("expected the outer handler to have handled the effect"),
+    }
+  }
+
+  #[test]
+  fn compose_handlers_does_not_forward_non_decline_errors() {
+    let mut composed = compose_handlers(Unreachable, Fail);
+    let mut ctx = MockContainer;
+    let result = composed.execute(vec![1], &mut ctx);
+    assert!(matches!(result, Err(Error { kind: ErrorKind::Type, .. })));
+  }
+
+  #[test]
+  fn filter_handler_first_matching_rule_wins() {
+    let mut filter = FilterHandler::new(Box::new(Unreachable))
+      .rule(Box::new(|_ctx: &MockContainer, _args: &[i32]| true), FilterAction::Drop)
+      .rule(Box::new(|_ctx: &MockContainer, _args: &[i32]| true), FilterAction::Perform);
+    let mut ctx = MockContainer;
+    match filter.execute(vec![7], &mut ctx) {
+      Ok(result) => assert_eq!(result, vec![7]),
+      Err(_) => panic!("expected the first rule's Drop action to apply"),
+    }
+  }
+
+  #[test]
+  fn filter_handler_falls_through_to_inner_when_no_rule_matches() {
+    let mut filter = FilterHandler::new(Box::new(Echo))
+      .rule(Box::new(|_ctx: &MockContainer, _args: &[i32]| false), FilterAction::Drop);
+    let mut ctx = MockContainer;
+    match filter.execute(vec![9], &mut ctx) {
+      Ok(result) => assert_eq!(result, vec![9]),
+      Err(_) => panic!("expected the inner handler to have handled the effect"),
+    }
+  }
+}